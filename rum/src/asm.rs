@@ -0,0 +1,295 @@
+// Text assembler and disassembler for the UM instruction set.
+//
+// Mnemonics mirror the `Opcode` enum in `machine`, and operands are
+// encoded/decoded using the exact bit layout already used by
+// `Instruction::decode` / `parse_opcode`, so a program assembled from
+// this text format produces the same words `boot()` would load from a
+// `.um` binary, and disassembling those words round-trips back to text.
+
+use crate::machine::{Instruction, Opcode};
+
+/// An error encountered while assembling UM source text.
+#[derive(Debug, PartialEq)]
+pub enum AsmError {
+    UnknownMnemonic { line: usize, mnemonic: String },
+    WrongOperandCount { line: usize, expected: usize, found: usize },
+    InvalidRegister { line: usize, text: String },
+    InvalidImmediate { line: usize, text: String },
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AsmError::UnknownMnemonic { line, mnemonic } => {
+                write!(f, "line {}: unknown mnemonic '{}'", line, mnemonic)
+            }
+            AsmError::WrongOperandCount { line, expected, found } => write!(
+                f,
+                "line {}: expected {} operand(s), found {}",
+                line, expected, found
+            ),
+            AsmError::InvalidRegister { line, text } => {
+                write!(f, "line {}: invalid register '{}'", line, text)
+            }
+            AsmError::InvalidImmediate { line, text } => {
+                write!(f, "line {}: invalid immediate '{}'", line, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Which of the ra/rb/rc bit fields a mnemonic's operands occupy, in the
+// order they're written in source. `halt` takes no operands; `loadimm`
+// has no slots here because its register and immediate are handled as a
+// special case below.
+fn register_slots(op: &Opcode) -> &'static [char] {
+    match op {
+        Opcode::CMov | Opcode::Load | Opcode::Store | Opcode::Add
+        | Opcode::Mul | Opcode::Div | Opcode::Nand => &['a', 'b', 'c'],
+        Opcode::Halt => &[],
+        Opcode::MapSegment | Opcode::LoadProgram => &['b', 'c'],
+        Opcode::UnmapSegment | Opcode::Output | Opcode::Input => &['c'],
+        Opcode::LoadValue => &[],
+    }
+}
+
+fn mnemonic_to_opcode(mnemonic: &str) -> Option<Opcode> {
+    Some(match mnemonic {
+        "cmov" => Opcode::CMov,
+        "load" => Opcode::Load,
+        "store" => Opcode::Store,
+        "add" => Opcode::Add,
+        "mul" => Opcode::Mul,
+        "div" => Opcode::Div,
+        "nand" => Opcode::Nand,
+        "halt" => Opcode::Halt,
+        "map" => Opcode::MapSegment,
+        "unmap" => Opcode::UnmapSegment,
+        "out" => Opcode::Output,
+        "in" => Opcode::Input,
+        "loadprog" => Opcode::LoadProgram,
+        "loadimm" => Opcode::LoadValue,
+        _ => return None,
+    })
+}
+
+fn opcode_to_mnemonic(op: &Opcode) -> &'static str {
+    match op {
+        Opcode::CMov => "cmov",
+        Opcode::Load => "load",
+        Opcode::Store => "store",
+        Opcode::Add => "add",
+        Opcode::Mul => "mul",
+        Opcode::Div => "div",
+        Opcode::Nand => "nand",
+        Opcode::Halt => "halt",
+        Opcode::MapSegment => "map",
+        Opcode::UnmapSegment => "unmap",
+        Opcode::Output => "out",
+        Opcode::Input => "in",
+        Opcode::LoadProgram => "loadprog",
+        Opcode::LoadValue => "loadimm",
+    }
+}
+
+fn parse_register(line: usize, text: &str) -> Result<u32, AsmError> {
+    let reg = text
+        .strip_prefix('r')
+        .and_then(|n| n.parse::<u32>().ok())
+        .filter(|&n| n < 8);
+    reg.ok_or_else(|| AsmError::InvalidRegister { line, text: text.to_string() })
+}
+
+fn parse_immediate(line: usize, text: &str) -> Result<u32, AsmError> {
+    let value = if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse::<u32>().ok()
+    };
+    value
+        .filter(|&v| v < (1 << 25))
+        .ok_or_else(|| AsmError::InvalidImmediate { line, text: text.to_string() })
+}
+
+/// Assembles UM source text into the big-endian-loaded word sequence
+/// `boot()` produces from a `.um` binary.
+///
+/// Each non-blank, non-comment line is either an instruction
+/// (`mnemonic operand...`) or a `.word 0xHHHHHHHH` directive for
+/// embedding a raw word, such as one with an illegal opcode (14/15).
+/// Comments start with `;` and run to the end of the line.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AsmError> {
+    let mut words = Vec::new();
+    for (line_no, raw_line) in source.lines().enumerate() {
+        let line = line_no + 1;
+        let text = match raw_line.find(';') {
+            Some(idx) => &raw_line[..idx],
+            None => raw_line,
+        };
+        let mut tokens = text.split_whitespace();
+        let mnemonic = match tokens.next() {
+            Some(m) => m,
+            None => continue,
+        };
+        let operands: Vec<&str> = tokens.collect();
+
+        if mnemonic == ".word" {
+            if operands.len() != 1 {
+                return Err(AsmError::WrongOperandCount {
+                    line,
+                    expected: 1,
+                    found: operands.len(),
+                });
+            }
+            let text = operands[0];
+            let raw = text
+                .strip_prefix("0x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| text.parse::<u32>().ok())
+                .ok_or_else(|| AsmError::InvalidImmediate {
+                    line,
+                    text: text.to_string(),
+                })?;
+            words.push(raw);
+            continue;
+        }
+
+        let opcode = mnemonic_to_opcode(mnemonic).ok_or_else(|| {
+            AsmError::UnknownMnemonic { line, mnemonic: mnemonic.to_string() }
+        })?;
+
+        let word = if opcode == Opcode::LoadValue {
+            if operands.len() != 2 {
+                return Err(AsmError::WrongOperandCount {
+                    line,
+                    expected: 2,
+                    found: operands.len(),
+                });
+            }
+            let ra = parse_register(line, operands[0])?;
+            let value = parse_immediate(line, operands[1])?;
+            ((Opcode::LoadValue as u32) << 28) | (ra << 25) | value
+        } else {
+            let slots = register_slots(&opcode);
+            if operands.len() != slots.len() {
+                return Err(AsmError::WrongOperandCount {
+                    line,
+                    expected: slots.len(),
+                    found: operands.len(),
+                });
+            }
+            let mut ra = 0;
+            let mut rb = 0;
+            let mut rc = 0;
+            for (&slot, text) in slots.iter().zip(operands.iter()) {
+                let reg = parse_register(line, text)?;
+                match slot {
+                    'a' => ra = reg,
+                    'b' => rb = reg,
+                    'c' => rc = reg,
+                    _ => unreachable!(),
+                }
+            }
+            ((opcode as u32) << 28) | (ra << 6) | (rb << 3) | rc
+        };
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Disassembles a sequence of words, such as the one `boot()` loads
+/// from a `.um` binary, back into the text format `assemble` accepts.
+pub fn disassemble(words: &[u32]) -> String {
+    let mut lines = Vec::with_capacity(words.len());
+    for &word in words {
+        match Instruction::decode(word) {
+            Some(instr) => lines.push(disassemble_instruction(&instr)),
+            None => {
+                let illegal_opcode = (word >> 28) & 0b1111;
+                lines.push(format!(
+                    "; illegal opcode {} (raw 0x{:08X})\n.word 0x{:08X}",
+                    illegal_opcode, word, word
+                ));
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+fn disassemble_instruction(instr: &Instruction) -> String {
+    let mnemonic = opcode_to_mnemonic(&instr.opcode);
+    match instr.opcode {
+        Opcode::Halt => mnemonic.to_string(),
+        Opcode::LoadValue => format!("{} r{} {}", mnemonic, instr.ra, instr.value),
+        _ => {
+            let regs: Vec<String> = register_slots(&instr.opcode)
+                .iter()
+                .map(|&slot| match slot {
+                    'a' => format!("r{}", instr.ra),
+                    'b' => format!("r{}", instr.rb),
+                    'c' => format!("r{}", instr.rc),
+                    _ => unreachable!(),
+                })
+                .collect();
+            format!("{} {}", mnemonic, regs.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_three_register_instruction() {
+        let words = assemble("cmov r1 r2 r3").unwrap();
+        assert_eq!(words, vec![(1u32 << 6) | (2 << 3) | 3]);
+    }
+
+    #[test]
+    fn assembles_loadimm() {
+        let words = assemble("loadimm r4 12345").unwrap();
+        assert_eq!(words, vec![(13u32 << 28) | (4 << 25) | 12345]);
+    }
+
+    #[test]
+    fn round_trips_a_small_program() {
+        let source = "\
+loadimm r0 72
+loadimm r1 105
+out r0
+out r1
+halt";
+        let words = assemble(source).unwrap();
+        let disassembled = disassemble(&words);
+        let reassembled = assemble(&disassembled).unwrap();
+        assert_eq!(words, reassembled);
+    }
+
+    #[test]
+    fn round_trips_illegal_opcode_markers() {
+        let words = vec![0xF0000000];
+        let text = disassemble(&words);
+        assert_eq!(assemble(&text).unwrap(), words);
+    }
+
+    #[test]
+    fn rejects_unknown_mnemonic() {
+        let err = assemble("frobnicate r0 r1 r2").unwrap_err();
+        assert!(matches!(err, AsmError::UnknownMnemonic { .. }));
+    }
+
+    #[test]
+    fn rejects_out_of_range_register() {
+        let err = assemble("cmov r8 r0 r0").unwrap_err();
+        assert!(matches!(err, AsmError::InvalidRegister { .. }));
+    }
+
+    #[test]
+    fn ignores_comments_and_blank_lines() {
+        let words = assemble("; a comment\n\nhalt ; trailing comment").unwrap();
+        assert_eq!(words, vec![7u32 << 28]);
+    }
+}
@@ -0,0 +1,114 @@
+// A small interactive front end for single-stepping a UM program,
+// inspecting its registers and memory, and setting breakpoints. Built
+// on top of `machine::Machine`'s `step`, rather than `machine::run`'s
+// run-to-completion loop.
+
+use rum::machine::{self, Machine, StepOutcome};
+use std::collections::HashSet;
+use std::env;
+use std::io::{self, Write};
+
+fn main() {
+    let filename = env::args().nth(1).expect("Usage: rumdb progname");
+    let instructions = machine::boot(&filename);
+    let mut vm = match Machine::new(instructions) {
+        Ok(vm) => vm,
+        Err(trap) => {
+            eprintln!("{}", trap);
+            return;
+        }
+    };
+    let mut breakpoints: HashSet<u32> = HashSet::new();
+
+    println!("rumdb - type 'h' for help");
+    loop {
+        print!("({:06}) > ", vm.pc());
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("s") => step_once(&mut vm),
+            Some("c") => continue_until_stop(&mut vm, &breakpoints),
+            Some("b") => match tokens.next().and_then(|pc| pc.parse().ok()) {
+                Some(pc) => {
+                    breakpoints.insert(pc);
+                    println!("breakpoint set at pc {}", pc);
+                }
+                None => eprintln!("usage: b <pc>"),
+            },
+            Some("r") => dump_registers(&vm),
+            Some("x") => {
+                let seg = tokens.next().and_then(|s| s.parse().ok());
+                let addr = tokens.next().and_then(|s| s.parse().ok());
+                match (seg, addr) {
+                    (Some(seg), Some(addr)) => match vm.peek(seg, addr) {
+                        Ok(word) => {
+                            println!("seg {} [{}] = 0x{:08X}", seg, addr, word)
+                        }
+                        Err(trap) => eprintln!("{}", trap),
+                    },
+                    _ => eprintln!("usage: x <seg> <addr>"),
+                }
+            }
+            Some("h") => print_help(),
+            Some("q") => break,
+            Some(other) => eprintln!("unknown command '{}' (try 'h')", other),
+            None => {}
+        }
+    }
+}
+
+fn step_once(vm: &mut Machine) {
+    match vm.step() {
+        Ok(StepOutcome::Continue) => {}
+        Ok(StepOutcome::Halted { instructions }) => {
+            println!("halted after {} instructions", instructions);
+        }
+        Err(trap) => println!("{}", trap),
+    }
+}
+
+// Steps until a breakpoint is reached, the program halts, or it traps.
+// The instruction at the current pc always runs first, so re-issuing
+// `c` at a just-hit breakpoint makes progress instead of re-triggering
+// it immediately.
+fn continue_until_stop(vm: &mut Machine, breakpoints: &HashSet<u32>) {
+    loop {
+        match vm.step() {
+            Ok(StepOutcome::Continue) => {
+                if breakpoints.contains(&vm.pc()) {
+                    println!("breakpoint hit at pc {}", vm.pc());
+                    return;
+                }
+            }
+            Ok(StepOutcome::Halted { instructions }) => {
+                println!("halted after {} instructions", instructions);
+                return;
+            }
+            Err(trap) => {
+                println!("{}", trap);
+                return;
+            }
+        }
+    }
+}
+
+fn dump_registers(vm: &Machine) {
+    for (i, value) in vm.registers().iter().enumerate() {
+        print!("r{}=0x{:08X} ", i, value);
+    }
+    println!("pc={}", vm.pc());
+}
+
+fn print_help() {
+    println!("s                step one instruction");
+    println!("c                continue until a breakpoint, halt, or trap");
+    println!("b <pc>           set a breakpoint before the word at pc");
+    println!("r                dump registers and pc");
+    println!("x <seg> <addr>   peek a word in memory");
+    println!("q                quit");
+}
@@ -0,0 +1,65 @@
+// Devices bound to reserved high segment ids, so `Load`/`Store` against
+// them dispatch to a peripheral instead of RAM.
+
+/// A memory-mapped peripheral. `reg` is the address a `Load`/`Store`
+/// used within the device's bound segment, letting a device expose more
+/// than one readable/writable value.
+pub trait Device {
+    fn read(&mut self, reg: u32) -> u32;
+    fn write(&mut self, reg: u32, value: u32);
+    /// Advances the device by `cycles`. Called once per instruction
+    /// `Machine::step` executes.
+    fn tick(&mut self, cycles: u64);
+}
+
+/// Segment id the free-running timer is bound to in a freshly-built
+/// `Machine`.
+pub const TIMER_SEGMENT: u32 = 0xFFFF_FFFF;
+
+/// A free-running timer that advances by one cycle per executed
+/// instruction and wraps at `u32::MAX`, so a program can poll elapsed
+/// cycles with a `Load` against `TIMER_SEGMENT`. Writes are ignored.
+#[derive(Debug, Default)]
+pub struct Timer {
+    elapsed: u32,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer { elapsed: 0 }
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, _reg: u32) -> u32 {
+        self.elapsed
+    }
+
+    fn write(&mut self, _reg: u32, _value: u32) {}
+
+    fn tick(&mut self, cycles: u64) {
+        self.elapsed = self.elapsed.wrapping_add(cycles as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_advances_with_ticks() {
+        let mut timer = Timer::new();
+        timer.tick(41);
+        assert_eq!(timer.read(0), 41);
+    }
+
+    #[test]
+    fn timer_wraps_at_u32_max() {
+        let mut timer = Timer::new();
+        timer.tick(u32::MAX as u64);
+        assert_eq!(timer.read(0), u32::MAX);
+
+        timer.tick(1);
+        assert_eq!(timer.read(0), 0);
+    }
+}
@@ -0,0 +1,135 @@
+// An alternate execution backend for `Machine`, enabled by the
+// `threaded-dispatch` Cargo feature: a handler table instead of `step`'s
+// `match`, plus a per-pc cache of decoded instructions, invalidated
+// whenever segment 0 changes.
+
+use std::collections::HashMap;
+
+use crate::machine::{Instruction, Machine, Opcode, StepOutcome, Trap};
+
+type Handler = fn(&mut Machine, &Instruction) -> Result<StepOutcome, Trap>;
+
+const HANDLERS: [Handler; 14] = [
+    Machine::op_cmov,
+    Machine::op_load,
+    Machine::op_store,
+    Machine::op_add,
+    Machine::op_mul,
+    Machine::op_div,
+    Machine::op_nand,
+    |m, _| m.op_halt(),
+    Machine::op_map_segment,
+    Machine::op_unmap_segment,
+    Machine::op_output,
+    Machine::op_input,
+    Machine::op_load_program,
+    Machine::op_load_value,
+];
+
+/// Caches, per pc, the already-decoded instruction and the handler its
+/// opcode resolves to. Must be invalidated whenever segment 0's
+/// contents change underneath it.
+#[derive(Default)]
+pub struct TraceCache {
+    decoded: HashMap<u32, (Handler, Instruction)>,
+}
+
+impl TraceCache {
+    pub fn new() -> TraceCache {
+        TraceCache::default()
+    }
+
+    /// Drops every cached entry. Called whenever segment 0 may have
+    /// changed (a `Store` into it, or a `LoadProgram`).
+    pub fn invalidate(&mut self) {
+        self.decoded.clear();
+    }
+
+    fn resolve(
+        &mut self,
+        machine: &Machine,
+        pc: u32,
+    ) -> Result<(Handler, Instruction), Trap> {
+        if let Some(&entry) = self.decoded.get(&pc) {
+            return Ok(entry);
+        }
+        let instr = Instruction::decode_unchecked(machine.fetch(pc)?);
+        let entry = (HANDLERS[instr.opcode as usize], instr);
+        self.decoded.insert(pc, entry);
+        Ok(entry)
+    }
+}
+
+/// Runs one instruction through the handler table, consulting and
+/// extending `cache` for the pc it's fetched from. Equivalent to
+/// `Machine::step`, but only a self-modifying program pays for
+/// re-verifying and re-caching segment 0; straight-line execution
+/// skips the `match` and, after the first visit to a pc, the
+/// fetch/decode too.
+pub fn step_dispatch(
+    machine: &mut Machine,
+    cache: &mut TraceCache,
+) -> Result<StepOutcome, Trap> {
+    let pc = machine.pc();
+    let (handler, instr) = cache.resolve(machine, pc)?;
+
+    machine.begin_instruction();
+    let store_dest = match instr.opcode {
+        Opcode::Store => Some(machine.reg(instr.ra)),
+        _ => None,
+    };
+    let outcome = handler(machine, &instr)?;
+
+    match instr.opcode {
+        Opcode::Store if store_dest == Some(0) => cache.invalidate(),
+        Opcode::LoadProgram => cache.invalidate(),
+        _ => {}
+    }
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_default_interpreter() {
+        let words = crate::asm::assemble(
+            "loadimm r0 2\nloadimm r1 3\nadd r2 r0 r1\nhalt",
+        )
+        .unwrap();
+
+        let mut reference = Machine::new(words.clone()).unwrap();
+        loop {
+            if let StepOutcome::Halted { .. } = reference.step().unwrap() {
+                break;
+            }
+        }
+
+        let mut dispatched = Machine::new(words).unwrap();
+        let mut cache = TraceCache::new();
+        loop {
+            if let StepOutcome::Halted { .. } =
+                step_dispatch(&mut dispatched, &mut cache).unwrap()
+            {
+                break;
+            }
+        }
+
+        assert_eq!(reference.registers(), dispatched.registers());
+    }
+
+    #[test]
+    fn invalidate_clears_cached_entries() {
+        let words = crate::asm::assemble("halt").unwrap();
+        let machine = Machine::new(words).unwrap();
+        let mut cache = TraceCache::new();
+
+        cache.resolve(&machine, 0).unwrap();
+        assert_eq!(cache.decoded.len(), 1);
+
+        cache.invalidate();
+        assert!(cache.decoded.is_empty());
+    }
+}
@@ -0,0 +1,9 @@
+pub mod asm;
+pub mod device;
+// Alternate execution backend; off by default so `machine::Machine::step`'s
+// plain `match` interpreter stays the only code path most builds exercise.
+// Requires a `[features] threaded-dispatch = []` entry in Cargo.toml.
+#[cfg(feature = "threaded-dispatch")]
+pub mod dispatch;
+pub mod machine;
+pub mod memory;
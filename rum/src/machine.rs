@@ -1,89 +1,385 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{stdin, stdout};
-use std::process;
 
+use crate::device::{self, Device, Timer};
 use crate::memory;
 
-pub fn run(program: Vec<u32>) {
-    // Takes an in-memory executable image
-    // as specified by the UM spec, and executes it
-    // It is a c.r.e. if an instruction word has
-    // an invalid opcode (14 or 15).
-    let mut segmap = memory::Memory::new(program);
-    // next, start calling decode() on each instruction
-    // and dispatch it!
-    let mut r = Registers::new();
-    let mut pc = 0_u32;
-    let mut inst_counter = 0_u64;
-    loop {
-        let instr = match Instruction::decode(segmap.get_instruction(pc)) {
-            Some(instr) => instr,
-            None => panic!("illegal instruction"),
-        };
-        let op = instr.opcode;
-        inst_counter += 1;
-        pc += 1;
-        match op {
-            Opcode::CMov => {
-                if r[instr.rc] != 0 {
-                    r[instr.ra] = r[instr.rb]
-                }
-            }
-            Opcode::Load => {
-                r[instr.ra] = segmap.load(r[instr.rb], r[instr.rc]);
-            }
-            Opcode::Store => {
-                segmap.store(r[instr.ra], r[instr.rb], r[instr.rc]);
-            }
-            Opcode::Add => {
-                r[instr.ra] = r[instr.rb] + r[instr.rc];
-            }
-            Opcode::Mul => {
-                r[instr.ra] = r[instr.rb] * r[instr.rc];
-            }
-            Opcode::Div => {
-                r[instr.ra] = r[instr.rb] / r[instr.rc];
-            }
-            Opcode::Nand => {
-                r[instr.ra] = !(r[instr.rb] & r[instr.rc]);
-            }
-            Opcode::Halt => {
-                eprintln!("{} instructions executed", inst_counter);
-                process::exit(0);
-            }
-            Opcode::MapSegment => {
-                r[instr.rb] = segmap.allocate(r[instr.rc]);
+/// A fault raised by the interpreter loop. Unlike the panics this
+/// replaces, a `Trap` is ordinary data: an embedder can match on it and
+/// decide what to do, rather than having the process torn down out from
+/// under it. `Halted` is included here too (rather than being the `Ok`
+/// case) so that `run` can report the final instruction count without
+/// needing a separate success payload.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Trap {
+    IllegalOpcode(u32),
+    UnmappedSegment(u32),
+    AddressOutOfBounds { seg: u32, addr: u32 },
+    DivByZero,
+    Halted { instructions: u64 },
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Trap::IllegalOpcode(word) => {
+                write!(f, "illegal opcode in word 0x{:08X}", word)
             }
-            Opcode::UnmapSegment => {
-                segmap.deallocate(r[instr.rc]);
+            Trap::UnmappedSegment(seg) => write!(f, "unmapped segment {}", seg),
+            Trap::AddressOutOfBounds { seg, addr } => {
+                write!(f, "address {} out of bounds in segment {}", addr, seg)
             }
-            Opcode::Output => {
-                let value = r[instr.rc] as u8;
-                stdout().write_all(&[value]).unwrap();
-                stdout().flush().unwrap();
+            Trap::DivByZero => write!(f, "division by zero"),
+            Trap::Halted { instructions } => {
+                write!(f, "halted after {} instructions", instructions)
             }
-            Opcode::Input => match stdin().bytes().next() {
-                Some(value) => {
-                    r[instr.rc] = value.unwrap() as u32;
-                }
-                None => r[instr.rc] = !0,
-            },
-            Opcode::LoadProgram => {
-                segmap.load_segment(r[instr.rb]);
-                pc = r[instr.rc];
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// A word in segment 0 whose opcode field doesn't decode to a known
+/// `Opcode` (i.e. it encodes 14 or 15).
+#[derive(Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    pub pc: u32,
+    pub word: u32,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "illegal opcode in word 0x{:08X} at pc {}",
+            self.word, self.pc
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<VerifyError> for Trap {
+    fn from(err: VerifyError) -> Trap {
+        Trap::IllegalOpcode(err.word)
+    }
+}
+
+/// Scans `program` once for any word whose opcode can't be decoded, so
+/// `step` can later fetch with `Instruction::decode_unchecked`. A segment
+/// that becomes segment 0 later (via `Store` or `LoadProgram`) is
+/// re-verified before execution resumes.
+pub fn verify(program: &[u32]) -> Result<(), VerifyError> {
+    for (pc, &word) in program.iter().enumerate() {
+        if parse_opcode(word).is_none() {
+            return Err(VerifyError { pc: pc as u32, word });
+        }
+    }
+    Ok(())
+}
+
+// Verifies a single word about to land in segment 0, e.g. from a `Store`
+// targeting it. Cheaper than re-running `verify` over the whole program,
+// and gives the same guarantee since only `word` itself is new.
+fn verify_word(pc: u32, word: u32) -> Result<(), VerifyError> {
+    if parse_opcode(word).is_none() {
+        return Err(VerifyError { pc, word });
+    }
+    Ok(())
+}
+
+/// What happened when a single instruction was executed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    Continue,
+    Halted { instructions: u64 },
+}
+
+/// The UM interpreter's state: the segment map, registers, program
+/// counter, instruction count, and any bound devices.
+pub struct Machine {
+    segmap: memory::Memory,
+    r: Registers,
+    pc: u32,
+    inst_counter: u64,
+    devices: HashMap<u32, Box<dyn Device>>,
+}
+
+impl Machine {
+    /// Verifies `program`, loads it as segment 0, and binds a
+    /// free-running timer at `device::TIMER_SEGMENT`.
+    pub fn new(program: Vec<u32>) -> Result<Machine, Trap> {
+        verify(&program)?;
+        let mut devices: HashMap<u32, Box<dyn Device>> = HashMap::new();
+        devices.insert(device::TIMER_SEGMENT, Box::new(Timer::new()));
+        Ok(Machine {
+            segmap: memory::Memory::new(program),
+            r: Registers::new(),
+            pc: 0,
+            inst_counter: 0,
+            devices,
+        })
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.inst_counter
+    }
+
+    pub fn registers(&self) -> [u32; 8] {
+        self.r.0
+    }
+
+    /// Binds `device` to `seg_id`, so `Load`/`Store` against that
+    /// segment id dispatch to it instead of RAM.
+    pub fn bind_device(&mut self, seg_id: u32, device: Box<dyn Device>) {
+        self.devices.insert(seg_id, device);
+    }
+
+    /// Reads a word out of the given segment, without affecting
+    /// execution state. Device-bound segments are read through the
+    /// device, same as `Load` would.
+    pub fn peek(&mut self, seg_id: u32, address: u32) -> Result<u32, Trap> {
+        self.load(seg_id, address)
+    }
+
+    fn load(&mut self, seg_id: u32, address: u32) -> Result<u32, Trap> {
+        match self.devices.get_mut(&seg_id) {
+            Some(device) => Ok(device.read(address)),
+            None => self.segmap.load(seg_id, address),
+        }
+    }
+
+    fn store(
+        &mut self,
+        seg_id: u32,
+        address: u32,
+        value: u32,
+    ) -> Result<(), Trap> {
+        match self.devices.get_mut(&seg_id) {
+            Some(device) => {
+                device.write(address, value);
+                Ok(())
             }
-            Opcode::LoadValue => {
-                r[instr.ra] = instr.value;
+            None => self.segmap.store(seg_id, address, value),
+        }
+    }
+
+    // the raw word at `pc` in segment 0, for the `dispatch` backend's
+    // own instruction cache.
+    #[cfg(feature = "threaded-dispatch")]
+    pub(crate) fn fetch(&self, pc: u32) -> Result<u32, Trap> {
+        self.segmap.get_instruction(pc)
+    }
+
+    #[cfg(feature = "threaded-dispatch")]
+    pub(crate) fn reg(&self, i: u32) -> u32 {
+        self.r[i]
+    }
+
+    fn tick_devices(&mut self) {
+        for device in self.devices.values_mut() {
+            device.tick(1);
+        }
+    }
+
+    // Bookkeeping common to every instruction, regardless of which
+    // dispatch backend runs it: advance `pc` and the instruction
+    // counter, then let devices observe the elapsed cycle.
+    pub(crate) fn begin_instruction(&mut self) {
+        self.inst_counter += 1;
+        self.pc += 1;
+        self.tick_devices();
+    }
+
+    /// Executes exactly one instruction, fetched from the current `pc`.
+    pub fn step(&mut self) -> Result<StepOutcome, Trap> {
+        let word = self.segmap.get_instruction(self.pc)?;
+        let instr = Instruction::decode_unchecked(word);
+        self.begin_instruction();
+        match instr.opcode {
+            Opcode::CMov => self.op_cmov(&instr),
+            Opcode::Load => self.op_load(&instr),
+            Opcode::Store => self.op_store(&instr),
+            Opcode::Add => self.op_add(&instr),
+            Opcode::Mul => self.op_mul(&instr),
+            Opcode::Div => self.op_div(&instr),
+            Opcode::Nand => self.op_nand(&instr),
+            Opcode::Halt => self.op_halt(),
+            Opcode::MapSegment => self.op_map_segment(&instr),
+            Opcode::UnmapSegment => self.op_unmap_segment(&instr),
+            Opcode::Output => self.op_output(&instr),
+            Opcode::Input => self.op_input(&instr),
+            Opcode::LoadProgram => self.op_load_program(&instr),
+            Opcode::LoadValue => self.op_load_value(&instr),
+        }
+    }
+
+    // One method per opcode, each returning the same `StepOutcome` that
+    // `step`'s `match` returns. Pulling these out of the `match` lets
+    // the feature-gated table-dispatch backend in `dispatch` reuse the
+    // exact same logic instead of re-implementing it.
+
+    pub(crate) fn op_cmov(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        if self.r[instr.rc] != 0 {
+            self.r[instr.ra] = self.r[instr.rb]
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_load(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.r[instr.ra] = self.load(self.r[instr.rb], self.r[instr.rc])?;
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_store(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        let dest = self.r[instr.ra];
+        let address = self.r[instr.rb];
+        let value = self.r[instr.rc];
+        self.store(dest, address, value)?;
+        if dest == 0 {
+            verify_word(address, value)?;
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_add(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.r[instr.ra] = self.r[instr.rb].wrapping_add(self.r[instr.rc]);
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_mul(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.r[instr.ra] = self.r[instr.rb].wrapping_mul(self.r[instr.rc]);
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_div(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        if self.r[instr.rc] == 0 {
+            return Err(Trap::DivByZero);
+        }
+        self.r[instr.ra] = self.r[instr.rb] / self.r[instr.rc];
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_nand(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.r[instr.ra] = !(self.r[instr.rb] & self.r[instr.rc]);
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_halt(&mut self) -> Result<StepOutcome, Trap> {
+        Ok(StepOutcome::Halted { instructions: self.inst_counter })
+    }
+
+    pub(crate) fn op_map_segment(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.r[instr.rb] = self.segmap.allocate(self.r[instr.rc]);
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_unmap_segment(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.segmap.deallocate(self.r[instr.rc])?;
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_output(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        let value = self.r[instr.rc] as u8;
+        stdout().write_all(&[value]).unwrap();
+        stdout().flush().unwrap();
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_input(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        match stdin().bytes().next() {
+            Some(value) => self.r[instr.rc] = value.unwrap() as u32,
+            None => self.r[instr.rc] = !0,
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_load_program(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.segmap.load_segment(self.r[instr.rb])?;
+        verify(self.segmap.segment0())?;
+        self.pc = self.r[instr.rc];
+        Ok(StepOutcome::Continue)
+    }
+
+    pub(crate) fn op_load_value(
+        &mut self,
+        instr: &Instruction,
+    ) -> Result<StepOutcome, Trap> {
+        self.r[instr.ra] = instr.value;
+        Ok(StepOutcome::Continue)
+    }
+}
+
+pub fn run(program: Vec<u32>) -> Result<(), Trap> {
+    // Takes an in-memory executable image
+    // as specified by the UM spec, and executes it.
+    let mut machine = Machine::new(program)?;
+
+    #[cfg(feature = "threaded-dispatch")]
+    {
+        let mut cache = crate::dispatch::TraceCache::new();
+        loop {
+            let outcome = crate::dispatch::step_dispatch(&mut machine, &mut cache)?;
+            if let StepOutcome::Halted { instructions } = outcome {
+                return Err(Trap::Halted { instructions });
             }
         }
     }
+
+    #[cfg(not(feature = "threaded-dispatch"))]
+    loop {
+        if let StepOutcome::Halted { instructions } = machine.step()? {
+            return Err(Trap::Halted { instructions });
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u32)]
-enum Opcode {
+pub(crate) enum Opcode {
     CMov,
     Load,
     Store,
@@ -123,7 +419,7 @@ pub fn boot(filename: &str) -> Vec<u32> {
 
 // functions for instruction parsing.
 
-fn parse_opcode(instruction: u32) -> Option<Opcode> {
+pub(crate) fn parse_opcode(instruction: u32) -> Option<Opcode> {
     Some(match (instruction >> 28) & 0b1111 {
         0 => Opcode::CMov,
         1 => Opcode::Load,
@@ -143,32 +439,65 @@ fn parse_opcode(instruction: u32) -> Option<Opcode> {
     })
 }
 
-#[derive(Debug)]
-struct Instruction {
-    opcode: Opcode,
-    ra: u32,
-    rb: u32,
-    rc: u32,
-    value: u32,
+// Same mapping as `parse_opcode`, without the `Option` wrapping. Only
+// safe to call on a word that has already passed `verify`.
+fn parse_opcode_unchecked(instruction: u32) -> Opcode {
+    match (instruction >> 28) & 0b1111 {
+        0 => Opcode::CMov,
+        1 => Opcode::Load,
+        2 => Opcode::Store,
+        3 => Opcode::Add,
+        4 => Opcode::Mul,
+        5 => Opcode::Div,
+        6 => Opcode::Nand,
+        7 => Opcode::Halt,
+        8 => Opcode::MapSegment,
+        9 => Opcode::UnmapSegment,
+        10 => Opcode::Output,
+        11 => Opcode::Input,
+        12 => Opcode::LoadProgram,
+        13 => Opcode::LoadValue,
+        other => unreachable!("unverified illegal opcode {}", other),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instruction {
+    pub(crate) opcode: Opcode,
+    pub(crate) ra: u32,
+    pub(crate) rb: u32,
+    pub(crate) rc: u32,
+    pub(crate) value: u32,
 }
 
 impl Instruction {
-    fn decode(instruction: u32) -> Option<Instruction> {
+    pub(crate) fn decode(instruction: u32) -> Option<Instruction> {
         let opcode = parse_opcode(instruction)?;
-        let mut inst = Instruction { opcode, ra: 0, rb: 0, rc: 0, value: 0 };
-        match inst.opcode {
-            Opcode::LoadValue => {
-                inst.ra = (instruction >> 25) & 0x7;
-                inst.value = (instruction << 7) >> 7;
-            }
-            _ => {
-                inst.ra = (instruction >> 6) & 0x7;
-                inst.rb = (instruction >> 3) & 0x7;
-                inst.rc = instruction & 0x7;
-            }
+        Some(decode_fields(instruction, opcode))
+    }
+
+    // Decodes `instruction` assuming its opcode field is already known to
+    // be legal (e.g. because its segment just passed `verify`). Skips the
+    // `Option` check `decode` has to do for an arbitrary word.
+    pub(crate) fn decode_unchecked(instruction: u32) -> Instruction {
+        decode_fields(instruction, parse_opcode_unchecked(instruction))
+    }
+}
+
+fn decode_fields(instruction: u32, opcode: Opcode) -> Instruction {
+    let mut inst = Instruction { opcode, ra: 0, rb: 0, rc: 0, value: 0 };
+    match inst.opcode {
+        Opcode::LoadValue => {
+            inst.ra = (instruction >> 25) & 0x7;
+            inst.value = (instruction << 7) >> 7;
+        }
+        _ => {
+            inst.ra = (instruction >> 6) & 0x7;
+            inst.rb = (instruction >> 3) & 0x7;
+            inst.rc = instruction & 0x7;
         }
-        Some(inst)
     }
+    inst
 }
 
 // A wrapper for encapsulating register logic. Makes it easier to experiment
@@ -202,3 +531,96 @@ impl std::ops::IndexMut<u32> for Registers {
         // unsafe { self.0.get_unchecked_mut(i as usize) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    #[test]
+    fn run_surfaces_div_by_zero() {
+        let program = asm::assemble("div r0 r0 r0\nhalt").unwrap();
+        assert_eq!(run(program), Err(Trap::DivByZero));
+    }
+
+    #[test]
+    fn run_surfaces_unmapped_segment() {
+        let program = asm::assemble(
+            "loadimm r1 99\nloadimm r2 0\nload r0 r1 r2\nhalt",
+        )
+        .unwrap();
+        assert_eq!(run(program), Err(Trap::UnmappedSegment(99)));
+    }
+
+    #[test]
+    fn run_surfaces_address_out_of_bounds() {
+        let program = asm::assemble(
+            "loadimm r1 0\nloadimm r2 999\nload r0 r1 r2\nhalt",
+        )
+        .unwrap();
+        assert_eq!(
+            run(program),
+            Err(Trap::AddressOutOfBounds { seg: 0, addr: 999 })
+        );
+    }
+
+    #[test]
+    fn wraps_add_on_overflow_instead_of_panicking() {
+        // Repeated doubling of the largest loadable immediate overflows
+        // a u32 register on the eighth (last) doubling, just before the
+        // program halts.
+        let doublings = 8;
+        let mut source = String::from("loadimm r0 0x1FFFFFF\n");
+        source.push_str(&"add r0 r0 r0\n".repeat(doublings));
+        source.push_str("halt");
+        let program = asm::assemble(&source).unwrap();
+
+        let mut machine = Machine::new(program).unwrap();
+        loop {
+            if let StepOutcome::Halted { .. } = machine.step().unwrap() {
+                break;
+            }
+        }
+
+        let mut expected: u32 = 0x1FFFFFF;
+        for _ in 0..doublings {
+            expected = expected.wrapping_add(expected);
+        }
+        assert_eq!(machine.registers()[0], expected);
+    }
+
+    #[test]
+    fn verify_accepts_only_legal_opcodes() {
+        let program = asm::assemble("cmov r0 r0 r0\nhalt").unwrap();
+        assert_eq!(verify(&program), Ok(()));
+    }
+
+    #[test]
+    fn verify_rejects_illegal_opcode_14() {
+        let program = vec![0xE000_0000];
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError { pc: 0, word: 0xE000_0000 })
+        );
+    }
+
+    #[test]
+    fn verify_rejects_illegal_opcode_15() {
+        let program = vec![0, 0xF000_0000];
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError { pc: 1, word: 0xF000_0000 })
+        );
+    }
+
+    #[test]
+    fn run_surfaces_address_out_of_bounds_when_pc_runs_off_the_program() {
+        // A single non-halting instruction: execution falls off the end
+        // of segment 0 instead of halting.
+        let program = asm::assemble("nand r0 r0 r0").unwrap();
+        assert_eq!(
+            run(program),
+            Err(Trap::AddressOutOfBounds { seg: 0, addr: 1 })
+        );
+    }
+}
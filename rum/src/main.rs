@@ -1,8 +1,14 @@
-use rum::machine;
+use rum::machine::{self, Trap};
 use std::env;
+use std::process;
 
 fn main() {
     let filename = env::args().nth(1).expect("Usage: rum progname");
     let instructions = machine::boot(&filename);
-    machine::run(instructions);
+    if let Err(trap) = machine::run(instructions) {
+        eprintln!("{}", trap);
+        if !matches!(trap, Trap::Halted { .. }) {
+            process::exit(1);
+        }
+    }
 }
@@ -1,82 +1,183 @@
-use std::collections::HashMap;
+use crate::machine::Trap;
+
 const PROGRAM_ADDRESS: u32 = 0;
 
 #[derive(Debug)]
 pub struct Memory {
-    pool: Vec<u32>,
-    heap: HashMap<u32, Vec<u32>>,
+    // indexed directly by segment id; no hashing on the hot path.
+    segments: Vec<Vec<u32>>,
+    // parallel to `segments`: whether the id is currently mapped. A freed
+    // id's slot is cleared but kept (so ids stay densely indexable), so
+    // this is what actually distinguishes "freed" from "mapped but empty".
+    live: Vec<bool>,
+    // reclaimed segment ids, available for reuse by `allocate`.
+    free: Vec<u32>,
 }
 
 impl Memory {
-    // create a new Memory, comprising a pool of reusable IDs
-    // and a heap of UM words, populated with the instructions
-    // as segment 0
+    // create a new Memory, comprising a free-list of reusable IDs
+    // and the instructions, populated as segment 0.
     pub fn new(instructions: Vec<u32>) -> Memory {
-        Memory { pool: vec![], heap: HashMap::from([(0_u32, instructions)]) }
+        Memory { segments: vec![instructions], live: vec![true], free: vec![] }
+    }
+
+    fn is_live(&self, seg_id: u32) -> bool {
+        self.live.get(seg_id as usize).copied().unwrap_or(false)
     }
 
     // allocate and initalize (as all 0s) a memory segment.
     // returns the segment ID
     pub fn allocate(&mut self, size: u32) -> u32 {
         // can we reuse a previously unmapped segment id?
-        match self.pool.pop() {
+        match self.free.pop() {
             None => {
-                let x = self.heap.len() as u32;
-                self.heap.insert(x, vec![0; size as usize]);
-                x
+                let id = self.segments.len() as u32;
+                self.segments.push(vec![0; size as usize]);
+                self.live.push(true);
+                id
             }
-            Some(address) => {
-                assert!(
-                    address < self.heap.len() as u32,
-                    "invalid address in pool"
-                );
-                self.heap.get_mut(&address).unwrap().resize(size as usize, 0);
-                address
+            Some(id) => {
+                self.segments[id as usize].resize(size as usize, 0);
+                self.live[id as usize] = true;
+                id
             }
         }
     }
 
-    // deallocate the memory at the given address.
-    pub fn deallocate(&mut self, address: u32) {
-        assert!(
-            address < self.heap.len() as u32,
-            "invalid address {}, cannot deallocate",
-            address,
-        );
-        self.pool.push(address);
-        self.heap.get_mut(&address).unwrap().clear();
+    // deallocate the memory at the given address. Segment 0 is always the
+    // running program, so it can never be freed.
+    pub fn deallocate(&mut self, address: u32) -> Result<(), Trap> {
+        if address == PROGRAM_ADDRESS || !self.is_live(address) {
+            return Err(Trap::UnmappedSegment(address));
+        }
+        self.segments[address as usize].clear();
+        self.live[address as usize] = false;
+        self.free.push(address);
+        Ok(())
+    }
+
+    // supply contents of the memory at the given address, or the trap
+    // describing why it couldn't be read.
+    pub fn load(&self, seg_id: u32, address: u32) -> Result<u32, Trap> {
+        if !self.is_live(seg_id) {
+            return Err(Trap::UnmappedSegment(seg_id));
+        }
+        self.segments[seg_id as usize]
+            .get(address as usize)
+            .copied()
+            .ok_or(Trap::AddressOutOfBounds { seg: seg_id, addr: address })
     }
 
-    // supply contents of the memory at the given address if
-    // initialized, panics otherwise.
-    pub fn load(&self, seg_id: u32, address: u32) -> u32 {
-        self.heap.get(&seg_id).unwrap()[address as usize]
+    // get the instruction word corresponding to the given program counter.
+    // Segment 0 is always the program, so this is a direct index with no
+    // hash lookup.
+    pub fn get_instruction(&self, pc: u32) -> Result<u32, Trap> {
+        self.segments[PROGRAM_ADDRESS as usize]
+            .get(pc as usize)
+            .copied()
+            .ok_or(Trap::AddressOutOfBounds { seg: PROGRAM_ADDRESS, addr: pc })
     }
 
-    // get the instruction word corresponding to the given program counter
-    // if it doesn't exist, then this panics
-    // This may have high overhead...
-    pub fn get_instruction(&self, pc: u32) -> u32 {
-        // SAFETY: `heap` always has length at least 1 and PROGRAM_ADDRESS
-        // is always == 0. This improves performance by about 10%.
-        self.heap.get(&PROGRAM_ADDRESS).unwrap()[pc as usize]
+    // the full contents of segment 0, for re-verifying after it changes.
+    pub(crate) fn segment0(&self) -> &[u32] {
+        &self.segments[PROGRAM_ADDRESS as usize]
     }
 
     // write a value into the given address of the given segment.
-    pub fn store(&mut self, seg_id: u32, address: u32, value: u32) {
-        let memory =
-            self.heap.get_mut(&seg_id).expect("Memory was unallocated");
-        memory[address as usize] = value;
+    pub fn store(
+        &mut self,
+        seg_id: u32,
+        address: u32,
+        value: u32,
+    ) -> Result<(), Trap> {
+        if !self.is_live(seg_id) {
+            return Err(Trap::UnmappedSegment(seg_id));
+        }
+        let slot = self.segments[seg_id as usize]
+            .get_mut(address as usize)
+            .ok_or(Trap::AddressOutOfBounds { seg: seg_id, addr: address })?;
+        *slot = value;
+        Ok(())
     }
 
     // replace the program with the vector at the given address
-    pub fn load_segment(&mut self, seg_id: u32) {
-        let program = self
-            .heap
-            .get(&seg_id)
-            .expect("Found no program at the given address")
-            .clone();
-        let dest = self.heap.get_mut(&PROGRAM_ADDRESS).unwrap();
-        *dest = program;
+    pub fn load_segment(&mut self, seg_id: u32) -> Result<(), Trap> {
+        if !self.is_live(seg_id) {
+            return Err(Trap::UnmappedSegment(seg_id));
+        }
+        let program = self.segments[seg_id as usize].clone();
+        self.segments[PROGRAM_ADDRESS as usize] = program;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_reuses_deallocated_ids() {
+        let mut mem = Memory::new(vec![0]);
+        let a = mem.allocate(4);
+        let b = mem.allocate(4);
+        assert_ne!(a, b);
+
+        mem.deallocate(a).unwrap();
+        assert_eq!(mem.allocate(4), a);
+
+        mem.deallocate(a).unwrap();
+        mem.deallocate(b).unwrap();
+        let mut reused = vec![mem.allocate(4), mem.allocate(4)];
+        reused.sort();
+        assert_eq!(reused, {
+            let mut ids = vec![a, b];
+            ids.sort();
+            ids
+        });
+    }
+
+    #[test]
+    fn deallocate_rejects_segment_zero() {
+        let mut mem = Memory::new(vec![0]);
+        assert_eq!(mem.deallocate(0), Err(Trap::UnmappedSegment(0)));
+    }
+
+    #[test]
+    fn deallocate_rejects_unmapped_segment() {
+        let mut mem = Memory::new(vec![0]);
+        assert_eq!(mem.deallocate(7), Err(Trap::UnmappedSegment(7)));
+    }
+
+    #[test]
+    fn load_after_deallocate_is_unmapped() {
+        let mut mem = Memory::new(vec![0]);
+        let seg = mem.allocate(4);
+        mem.deallocate(seg).unwrap();
+        assert_eq!(mem.load(seg, 0), Err(Trap::UnmappedSegment(seg)));
+    }
+
+    #[test]
+    fn deallocate_is_not_double_freeable() {
+        let mut mem = Memory::new(vec![0]);
+        let seg = mem.allocate(4);
+        mem.deallocate(seg).unwrap();
+        assert_eq!(mem.deallocate(seg), Err(Trap::UnmappedSegment(seg)));
+    }
+
+    #[test]
+    fn load_segment_rejects_a_freed_id() {
+        let mut mem = Memory::new(vec![0]);
+        let seg = mem.allocate(4);
+        mem.deallocate(seg).unwrap();
+        assert_eq!(mem.load_segment(seg), Err(Trap::UnmappedSegment(seg)));
+    }
+
+    #[test]
+    fn get_instruction_rejects_an_out_of_bounds_pc() {
+        let mem = Memory::new(vec![0]);
+        assert_eq!(
+            mem.get_instruction(1),
+            Err(Trap::AddressOutOfBounds { seg: 0, addr: 1 })
+        );
     }
 }